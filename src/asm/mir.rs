@@ -0,0 +1,127 @@
+//! Parsing for rustc's `--emit=mir` text output.
+
+use crate::options::OPTS;
+use std::path::Path;
+
+/// Result of searching a `.mir` file for a specific function.
+pub enum ParseResult {
+    /// The MIR text for the matched function.
+    Found(String),
+    /// No match; every function path seen, for the "did you mean?" table.
+    NotFound(Vec<String>),
+}
+
+/// Search `path` for the function whose path matches the one configured on
+/// the CLI (`OPTS.path()`), analogous to `asm::parse::function`.
+pub fn function(path: &Path) -> ParseResult {
+    let text = ::std::fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("failed to read {}: {}", path.display(), e));
+
+    let mut table = Vec::new();
+    let mut matched = None;
+
+    for (item_path, body) in functions(&text) {
+        table.push(item_path.clone());
+
+        if matched.is_none() {
+            if let Some(query) = OPTS.path() {
+                if matches(&item_path, &query) {
+                    matched = Some(body.to_string());
+                }
+            }
+        }
+    }
+
+    match matched {
+        Some(body) => ParseResult::Found(body),
+        None => ParseResult::NotFound(table),
+    }
+}
+
+/// Does `item_path` (e.g. `my_crate::my_mod::my_fn`) satisfy `query`? A
+/// query matches either the full path or just its leaf segment, the same
+/// leniency `asm::run`'s fuzzy lookup already affords assembly symbols.
+fn matches(item_path: &str, query: &str) -> bool {
+    item_path == query || item_path.rsplit("::").next() == Some(query)
+}
+
+/// Split a `.mir` file's text into `(item_path, body)` pairs, one per
+/// function, each `body` spanning from its header comment to the next one.
+fn functions(text: &str) -> Vec<(String, &str)> {
+    let mut starts = Vec::new();
+    for (offset, _) in text.match_indices("// MIR for `") {
+        if let Some(path) = mir_header_path(&text[offset..]) {
+            starts.push((offset, path));
+        }
+    }
+
+    let mut functions = Vec::new();
+    for (i, (offset, path)) in starts.iter().enumerate() {
+        let end = starts.get(i + 1).map_or(text.len(), |(next, _)| *next);
+        functions.push((path.clone(), &text[*offset..end]));
+    }
+    functions
+}
+
+/// Extract the backtick-quoted item path out of a `// MIR for \`path\` ...`
+/// header line.
+fn mir_header_path(from_header: &str) -> Option<String> {
+    let start = from_header.find('`')? + 1;
+    let rest = &from_header[start..];
+    let end = rest.find('`')?;
+    Some(rest[..end].to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = "\
+// MIR for `my_crate::foo` 0 mir_map
+fn foo(_1: i32) -> i32 {
+    let mut _0: i32;
+
+    bb0: {
+        _0 = _1;
+        return;
+    }
+}
+
+// MIR for `my_crate::bar::baz` 0 mir_map
+fn baz() -> () {
+    let mut _0: ();
+
+    bb0: {
+        return;
+    }
+}
+";
+
+    #[test]
+    fn mir_header_path_extracts_the_backtick_quoted_path() {
+        assert_eq!(
+            mir_header_path("// MIR for `my_crate::foo` 0 mir_map\nfn foo() {}"),
+            Some("my_crate::foo".to_string())
+        );
+        assert_eq!(mir_header_path("fn foo() {}"), None);
+    }
+
+    #[test]
+    fn functions_splits_one_block_per_header() {
+        let blocks = functions(SAMPLE);
+        assert_eq!(blocks.len(), 2);
+        assert_eq!(blocks[0].0, "my_crate::foo");
+        assert!(blocks[0].1.contains("fn foo(_1: i32) -> i32"));
+        assert!(!blocks[0].1.contains("fn baz"));
+        assert_eq!(blocks[1].0, "my_crate::bar::baz");
+        assert!(blocks[1].1.contains("fn baz() -> ()"));
+    }
+
+    #[test]
+    fn matches_accepts_full_path_or_leaf_segment() {
+        assert!(matches("my_crate::bar::baz", "my_crate::bar::baz"));
+        assert!(matches("my_crate::bar::baz", "baz"));
+        assert!(!matches("my_crate::bar::baz", "bar"));
+        assert!(!matches("my_crate::bar::baz", "qux"));
+    }
+}
@@ -1,5 +1,7 @@
 pub mod ast;
+pub mod mir;
 pub mod parse;
+pub mod snapshot;
 
 use crate::{options::*, target::TargetInfo};
 use parse::ParseResult;
@@ -27,11 +29,11 @@ impl FromStr for Style {
     }
 }
 
-fn parse_files(files: &[PathBuf], target: &TargetInfo) -> ParseResult {
+fn parse_files(files: &[(PathBuf, String)], target: &TargetInfo) -> ParseResult {
     if OPTS.debug_mode() {
         // In debug mode dump all the raw assembly that we could find.
-        for f in files {
-            debug!("raw file dump {}:", f.display());
+        for (f, crate_name) in files {
+            debug!("raw file dump {} (crate {}):", f.display(), crate_name);
             let fh = ::std::fs::File::open(f).unwrap();
             let file_buf = ::std::io::BufReader::new(&fh);
             for l in file_buf.lines() {
@@ -40,7 +42,7 @@ fn parse_files(files: &[PathBuf], target: &TargetInfo) -> ParseResult {
         }
     }
     let mut function_table = Vec::<String>::new();
-    for f in files {
+    for (f, _crate_name) in files {
         assert!(f.exists(), "path does not exist: {}", f.display());
         match self::parse::function(f.as_path(), target) {
             ParseResult::Found(function, files) => return ParseResult::Found(function, files),
@@ -56,10 +58,59 @@ fn parse_files(files: &[PathBuf], target: &TargetInfo) -> ParseResult {
     ParseResult::NotFound(function_table)
 }
 
-pub fn run(files: &[PathBuf], target: &TargetInfo) {
+/// Search `files` for the MIR block for the requested function and print it
+/// as-is. MIR is already readable Rust-ish text, so -- unlike assembly --
+/// there's no further semantic pass to run before display.
+fn run_mir(files: &[(PathBuf, String)]) {
+    if OPTS.debug_mode() {
+        for (f, crate_name) in files {
+            debug!("raw file dump {} (crate {}):", f.display(), crate_name);
+            let fh = ::std::fs::File::open(f).unwrap();
+            let file_buf = ::std::io::BufReader::new(&fh);
+            for l in file_buf.lines() {
+                debug!("{}", l.unwrap());
+            }
+        }
+    }
+
+    let mut function_table = Vec::<String>::new();
+    for (f, _crate_name) in files {
+        assert!(f.exists(), "path does not exist: {}", f.display());
+        match self::mir::function(f.as_path()) {
+            self::mir::ParseResult::Found(body) => {
+                println!("{}", body.trim_end());
+                return;
+            }
+            self::mir::ParseResult::NotFound(table) => function_table.extend(table),
+        }
+    }
+    function_table.sort();
+    function_table.dedup();
+    not_found(function_table);
+}
+
+pub fn run(files: &[(PathBuf, String)], target: &TargetInfo) {
+    if let crate::options::Options::Mir(_) = *OPTS.read() {
+        return run_mir(files);
+    }
+
     // Parse the files
     match parse_files(files, target) {
         self::parse::ParseResult::Found(mut function, file_table) => {
+            if let Some(snapshot_path) = OPTS.snapshot() {
+                match self::snapshot::check(&snapshot_path, &function.to_string(), OPTS.bless()) {
+                    Ok(()) => return,
+                    Err(diff) => {
+                        crate::display::write_error(&format!(
+                            "assembly does not match snapshot {}\n\n{}",
+                            snapshot_path.display(),
+                            diff
+                        ));
+                        ::std::process::exit(2);
+                    }
+                }
+            }
+
             // If we found the assembly for the path, we parse the assembly:
             let rust = crate::rust::parse(&function, &file_table);
 
@@ -75,49 +126,56 @@ pub fn run(files: &[PathBuf], target: &TargetInfo) {
                 crate::display::print(&mut function, rust, target);
             }
         }
-        ParseResult::NotFound(mut table) => match OPTS.path() {
-            None => {
-                for f in table {
-                    println!("{}", f);
-                }
+        ParseResult::NotFound(table) => not_found(table),
+    }
+}
+
+/// Print every known function path if no specific one was requested, or the
+/// closest fuzzy matches to the one that wasn't found. Shared between the
+/// assembly/LLVM-IR path and the MIR path, since neither mode found the
+/// requested function any differently.
+fn not_found(mut table: Vec<String>) {
+    match OPTS.path() {
+        None => {
+            for f in table {
+                println!("{}", f);
             }
-            Some(path) => {
-                use edit_distance::edit_distance;
-                let mut msg = format!(
-                    "could not find function at path \"{}\" in the generated assembly.\n",
-                    &path
-                );
-
-                let last_path = path;
-                let last_path = last_path.split(':').next_back().unwrap();
-                table.sort_by(|a, b| {
-                    edit_distance(a.split(':').next_back().unwrap(), last_path)
-                        .cmp(&edit_distance(b.split(':').next_back().unwrap(), last_path))
-                });
-
-                for (i, f) in table
-                    .iter()
-                    .take_while(|f| {
-                        edit_distance(f.split(':').next_back().unwrap(), last_path) <= 4
-                    })
-                    .enumerate()
-                {
-                    if i == 0 {
-                        msg.push_str("Is it one of the following functions?\n\n");
-                    }
-                    msg.push_str(&format!("  {}\n", f));
+        }
+        Some(path) => {
+            use edit_distance::edit_distance;
+            let mut msg = format!(
+                "could not find function at path \"{}\" in the generated assembly.\n",
+                &path
+            );
+
+            let last_path = path;
+            let last_path = last_path.split(':').next_back().unwrap();
+            table.sort_by(|a, b| {
+                edit_distance(a.split(':').next_back().unwrap(), last_path)
+                    .cmp(&edit_distance(b.split(':').next_back().unwrap(), last_path))
+            });
+
+            for (i, f) in table
+                .iter()
+                .take_while(|f| edit_distance(f.split(':').next_back().unwrap(), last_path) <= 4)
+                .enumerate()
+            {
+                if i == 0 {
+                    msg.push_str("Is it one of the following functions?\n\n");
                 }
+                msg.push_str(&format!("  {}\n", f));
+            }
 
-                msg.push_str(r#"
+            msg.push_str(
+                r#"
 Tips:
 * make sure that the function is present in the final binary (e.g. if it's a generic function, make sure that it is actually monomorphized)
 * try to do a --clean build (sometimes changes are not picked up)
-"#
-                    );
+"#,
+            );
 
-                crate::display::write_error(&msg);
-                ::std::process::exit(1);
-            }
-        },
+            crate::display::write_error(&msg);
+            ::std::process::exit(1);
+        }
     }
 }
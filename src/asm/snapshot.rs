@@ -0,0 +1,261 @@
+//! Golden-file regression testing for a function's compiled assembly.
+
+use super::*;
+
+use std::{fs, path::Path};
+
+/// Normalize volatile tokens (local label numbers, absolute addresses,
+/// comment columns, `.loc` debug directives) so that semantically equivalent
+/// assembly compares equal across builds.
+pub fn normalize(text: &str) -> String {
+    text.lines()
+        .map(normalize_line)
+        .filter(|l| !l.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+fn normalize_line(line: &str) -> String {
+    let line = line.trim_end();
+
+    // `.loc` directives encode a file/line/column triple; drop them outright.
+    if line.trim_start().starts_with(".loc") {
+        return String::new();
+    }
+
+    // Comment columns (`# ...`) are wherever rustc/llvm felt like putting them.
+    let line = match line.find('#') {
+        Some(i) => &line[..i],
+        None => line,
+    };
+
+    normalize_addresses(&normalize_local_labels(line))
+}
+
+/// `.LBB0_3` -> `.LBB_`: the label index can shift between equivalent builds
+/// without the semantics changing.
+fn normalize_local_labels(line: &str) -> String {
+    let mut out = String::new();
+    let mut rest = line;
+    while let Some(pos) = rest.find(".LBB") {
+        out.push_str(&rest[..pos]);
+        let tail = &rest[pos + 4..];
+        let end = tail
+            .find(|c: char| !c.is_ascii_digit() && c != '_')
+            .unwrap_or(tail.len());
+        out.push_str(".LBB_");
+        rest = &tail[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Absolute addresses vary run to run (`0x7f3c9a001230` -> `0xADDR`), but a
+/// meaningful immediate (a page size, a struct offset, a bitmask) rarely
+/// needs more than 8 hex digits, so only mask runs longer than that.
+const MIN_ADDRESS_HEX_DIGITS: usize = 9;
+
+fn normalize_addresses(line: &str) -> String {
+    let mut out = String::new();
+    let mut rest = line;
+    while let Some(pos) = rest.find("0x") {
+        out.push_str(&rest[..pos]);
+        let tail = &rest[pos + 2..];
+        let end = tail
+            .find(|c: char| !c.is_ascii_hexdigit())
+            .unwrap_or(tail.len());
+        if end >= MIN_ADDRESS_HEX_DIGITS {
+            out.push_str("0xADDR");
+        } else {
+            out.push_str("0x");
+            out.push_str(&tail[..end]);
+        }
+        rest = &tail[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Compare `actual` against the golden file at `path`.
+///
+/// If `bless` is set, or the golden file doesn't exist yet, the normalized
+/// `actual` is (re)written to `path`. Otherwise the golden file is normalized and
+/// compared against a normalized `actual`; on mismatch a unified diff of
+/// expected vs. actual is returned.
+pub fn check(path: &Path, actual: &str, bless: bool) -> Result<(), String> {
+    let actual = normalize(actual);
+
+    if bless || !path.exists() {
+        fs::write(path, &actual)
+            .unwrap_or_else(|e| panic!("failed to write snapshot {}: {}", path.display(), e));
+        debug!("wrote snapshot {}", path.display());
+        return Ok(());
+    }
+
+    let expected = fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("failed to read snapshot {}: {}", path.display(), e));
+    let expected = normalize(&expected);
+
+    if expected == actual {
+        return Ok(());
+    }
+
+    Err(unified_diff(&expected, &actual))
+}
+
+/// A unified-style line diff, good enough to show what moved.
+fn unified_diff(expected: &str, actual: &str) -> String {
+    let a: Vec<&str> = expected.lines().collect();
+    let b: Vec<&str> = actual.lines().collect();
+    let matches = longest_common_subsequence(&a, &b);
+
+    let mut diff = String::from("--- expected\n+++ actual\n");
+    let (mut i, mut j) = (0, 0);
+    for (li, ri) in matches {
+        while i < li {
+            diff.push_str(&format!("-{}\n", a[i]));
+            i += 1;
+        }
+        while j < ri {
+            diff.push_str(&format!("+{}\n", b[j]));
+            j += 1;
+        }
+        diff.push_str(&format!(" {}\n", a[li]));
+        i += 1;
+        j += 1;
+    }
+    while i < a.len() {
+        diff.push_str(&format!("-{}\n", a[i]));
+        i += 1;
+    }
+    while j < b.len() {
+        diff.push_str(&format!("+{}\n", b[j]));
+        j += 1;
+    }
+    diff
+}
+
+/// Standard O(n*m) dynamic-programming LCS, returning matched `(a, b)`
+/// index pairs in order.
+fn longest_common_subsequence(a: &[&str], b: &[&str]) -> Vec<(usize, usize)> {
+    let (n, m) = (a.len(), b.len());
+    let mut table = vec![vec![0usize; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            table[i][j] = if a[i] == b[j] {
+                table[i + 1][j + 1] + 1
+            } else {
+                table[i + 1][j].max(table[i][j + 1])
+            };
+        }
+    }
+
+    let mut pairs = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n && j < m {
+        if a[i] == b[j] {
+            pairs.push((i, j));
+            i += 1;
+            j += 1;
+        } else if table[i + 1][j] >= table[i][j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    pairs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_line_strips_loc_directives() {
+        assert_eq!(normalize_line("\t.loc 1 42 5"), "");
+    }
+
+    #[test]
+    fn normalize_line_strips_comment_columns() {
+        assert_eq!(
+            normalize_line("\tmovq %rax, %rdi # some comment"),
+            "\tmovq %rax, %rdi "
+        );
+    }
+
+    #[test]
+    fn normalize_local_labels_drops_the_index() {
+        assert_eq!(normalize_local_labels("\tjne .LBB0_3"), "\tjne .LBB_");
+        assert_eq!(normalize_local_labels(".LBB12_7:"), ".LBB_:");
+    }
+
+    #[test]
+    fn normalize_addresses_masks_long_hex_literals() {
+        assert_eq!(
+            normalize_addresses("movq $0x7f3c9a001230, %rax"),
+            "movq $0xADDR, %rax"
+        );
+    }
+
+    #[test]
+    fn normalize_addresses_leaves_short_hex_alone() {
+        // Short immediates (e.g. a shift amount) aren't addresses -- leave
+        // them as-is rather than masking everything starting with `0x`.
+        assert_eq!(normalize_addresses("shlq $0x4, %rax"), "shlq $0x4, %rax");
+    }
+
+    #[test]
+    fn normalize_addresses_leaves_a_meaningful_constant_alone() {
+        // A page size or struct offset is real codegen signal -- masking it
+        // away would hide an actual regression, not just run-to-run noise.
+        assert_eq!(
+            normalize_addresses("movl $0x1000, %edi"),
+            "movl $0x1000, %edi"
+        );
+    }
+
+    #[test]
+    fn normalize_is_stable_across_equivalent_builds() {
+        let a = "\tjne .LBB0_3\n\tmovq $0x7f3c9a001230, %rax # first run\n\t.loc 1 1 1";
+        let b = "\tjne .LBB0_9\n\tmovq $0x7f3c9a009999, %rax # second run\n\t.loc 1 9 9";
+        assert_eq!(normalize(a), normalize(b));
+    }
+
+    #[test]
+    fn longest_common_subsequence_finds_matching_pairs() {
+        let a = ["one", "two", "three"];
+        let b = ["zero", "one", "three", "four"];
+        assert_eq!(longest_common_subsequence(&a, &b), vec![(0, 1), (2, 2)]);
+    }
+
+    #[test]
+    fn check_writes_a_new_snapshot_on_first_run() {
+        let dir =
+            ::std::env::temp_dir().join(format!("cargo-asm-snapshot-test-{}", ::std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("new.s");
+        let _ = fs::remove_file(&path);
+
+        assert!(check(&path, "\tmovq %rax, %rdi", false).is_ok());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "\tmovq %rax, %rdi");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn check_reports_a_diff_on_mismatch() {
+        let dir = ::std::env::temp_dir().join(format!(
+            "cargo-asm-snapshot-test-mismatch-{}",
+            ::std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("existing.s");
+        fs::write(&path, "\tmovq %rax, %rdi").unwrap();
+
+        let err = check(&path, "\tmovq %rbx, %rdi", false).unwrap_err();
+        assert!(err.contains("-\tmovq %rax, %rdi"));
+        assert!(err.contains("+\tmovq %rbx, %rdi"));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -2,10 +2,7 @@
 
 use super::*;
 
-use std::{
-    path::{Path, PathBuf},
-    str::FromStr,
-};
+use std::{path::PathBuf, str::FromStr};
 
 /// Type of the build.
 #[derive(Copy, Clone, Debug)]
@@ -30,10 +27,78 @@ impl FromStr for Type {
     }
 }
 
-/// Builds the project according to the CLI options and returns a list of
-/// assembly files generated.
-pub fn project() -> Vec<PathBuf> {
-    use std::process::Command;
+/// A single `rustc` invocation that cargo printed as part of its `--verbose`
+/// output, used to work out exactly where a crate's `.s`/`.ll` file will land.
+///
+/// Cargo's `--message-format=json` `compiler-artifact` messages tell us
+/// *which* crate got built, but not where the `--emit`ted assembly/IR ends
+/// up, since cargo doesn't track that file as one of the crate's outputs.
+/// The verbose `Running \`rustc ...\`` lines do carry that information, via
+/// `--out-dir` and `-C extra-filename`.
+struct RustcInvocation {
+    crate_name: String,
+    out_dir: PathBuf,
+    extra_filename: String,
+    /// The full command line, kept around so callers can check whether this
+    /// particular invocation was actually passed the `--emit` flag they care
+    /// about (e.g. in per-crate mode, only the target crate's invocation is,
+    /// not its dependencies').
+    command: String,
+}
+
+/// Parse a single line of cargo's verbose output, returning the `rustc`
+/// invocation it describes, if any.
+fn parse_rustc_invocation(line: &str) -> Option<RustcInvocation> {
+    let start = line.find("Running `")? + "Running `".len();
+    let end = line.rfind('`')?;
+    let command = line.get(start..end)?;
+
+    let mut args = command.split_whitespace();
+    // The first token is the `rustc` binary cargo resolved, not the bare
+    // word `rustc` -- with no `RUSTC` override it's the absolute path into
+    // the active toolchain (e.g. `/root/.rustup/toolchains/.../bin/rustc`),
+    // and on Windows it carries a `.exe` suffix. Split on either separator
+    // rather than going through `std::path::Path`, since that only treats
+    // `\` as a separator when actually running on Windows, and we want to
+    // recognize a Windows-style path in cargo's output either way.
+    let program = args.next()?;
+    let file_name = program.rsplit(['/', '\\']).next().unwrap_or(program);
+    let stem = file_name.strip_suffix(".exe").unwrap_or(file_name);
+    if stem != "rustc" {
+        return None;
+    }
+
+    let mut crate_name = None;
+    let mut out_dir = None;
+    let mut extra_filename = String::new();
+
+    while let Some(arg) = args.next() {
+        match arg {
+            "--crate-name" => crate_name = args.next().map(|s| s.trim_matches('"').to_string()),
+            "--out-dir" => out_dir = args.next().map(|s| PathBuf::from(s.trim_matches('"'))),
+            "-C" => {
+                if let Some(value) = args.next().and_then(|s| s.strip_prefix("extra-filename=")) {
+                    extra_filename = value.trim_matches('"').to_string();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Some(RustcInvocation {
+        crate_name: crate_name?,
+        out_dir: out_dir?,
+        extra_filename,
+        command: command.to_string(),
+    })
+}
+
+/// Builds the project according to the CLI options and returns the list of
+/// generated assembly/IR files, each paired with the name of the crate that
+/// produced it.
+pub fn project() -> Vec<(PathBuf, String)> {
+    use std::io::{BufRead, BufReader};
+    use std::process::{Command, Stdio};
     debug!("Building project...");
 
     // Read the RUSTFLAGS environment variable
@@ -44,11 +109,16 @@ pub fn project() -> Vec<PathBuf> {
 
     debug!("RUSTFLAGS={}", rustflags);
 
-    // Compile project generating assembly output:
+    // `cargo rustc` scopes its trailing codegen flags to just the crate
+    // being analyzed, so dependencies build normally instead of every one of
+    // them being recompiled with `--emit asm`/`-C llvm-args=...` too. This is
+    // the default; `--whole-workspace` falls back to the old `cargo build` +
+    // global `RUSTFLAGS` behavior, which emits assembly for the whole
+    // dependency graph.
+    let per_crate = !OPTS.whole_workspace();
+
     let mut cargo_build = Command::new("cargo");
-    // TODO: unclear if `cargo build` + `RUSTFLAGS` should be used,
-    // or instead one should use `cargo rustc -- --emit asm`
-    cargo_build.arg("build");
+    cargo_build.arg(if per_crate { "rustc" } else { "build" });
     if !OPTS.no_color() {
         cargo_build.arg("--color=always");
         cargo_build.env("LS_COLORS", "rs=0:di=38;5;27:mh=44;38;5;15");
@@ -60,15 +130,24 @@ pub fn project() -> Vec<PathBuf> {
         cargo_build.arg("--release");
     }
     cargo_build.arg("--verbose");
+    cargo_build.arg("--message-format=json");
 
     if !OPTS.features().is_empty() {
         cargo_build.arg(&format!("--features={}", OPTS.features().join(",")));
     }
 
+    if let Some(package) = OPTS.package() {
+        cargo_build.arg(&format!("--package={}", package));
+    }
+
     if let Some(example) = OPTS.example() {
         cargo_build.arg(&format!("--example={}", example));
     }
 
+    if let Some(bin) = OPTS.bin() {
+        cargo_build.arg(&format!("--bin={}", bin));
+    }
+
     if OPTS.no_default_features() {
         cargo_build.arg("--no-default-features");
     }
@@ -91,7 +170,15 @@ pub fn project() -> Vec<PathBuf> {
 
     let ti = crate::target::TargetInfo::new_from_target();
 
-    match *OPTS.read() {
+    let emit_tag = match *OPTS.read() {
+        crate::options::Options::Asm(_) => "asm",
+        crate::options::Options::LlvmIr(_) => "llvm-ir",
+        crate::options::Options::Mir(_) => "mir",
+    };
+
+    // The codegen flags for the selected emit mode, independent of whether
+    // they end up in the global `RUSTFLAGS` or as trailing `cargo rustc` args.
+    let mut emit_flags = match *OPTS.read() {
         crate::options::Options::Asm(ref o) => {
             let asm_syntax = match o.asm_style {
                 crate::asm::Style::Intel if ti.is_intel() => "-C llvm-args=-x86-asm-syntax=intel",
@@ -104,62 +191,177 @@ pub fn project() -> Vec<PathBuf> {
                 ""
             };
 
-            cargo_build.env(
-                "RUSTFLAGS",
-                format!("{} --emit asm {} {}", rustflags, debug_info, asm_syntax),
-            );
+            format!("--emit=asm {} {}", debug_info, asm_syntax)
         }
         crate::options::Options::LlvmIr(ref _o) => {
             // TODO: the debug info really clutters the llvm-ir (-g)
-            cargo_build.env(
-                "RUSTFLAGS",
-                format!("{} -C debuginfo=0 --emit=llvm-ir", rustflags),
-            );
+            "-C debuginfo=0 --emit=llvm-ir".to_string()
+        }
+        crate::options::Options::Mir(ref _o) => "--emit=mir".to_string(),
+    };
+
+    // Let a cross build use the right toolchain instead of whatever the
+    // default host linker/archiver resolves to, same as rustbuild lets a
+    // target specify its own `linker`/`ar`:
+    if let Some(linker) = OPTS.linker() {
+        emit_flags.push_str(&format!(" -C linker={}", linker));
+    }
+    if let Some(archiver) = OPTS.archiver() {
+        // `rustc -C ar=...` has been a deprecated no-op for years; the
+        // archiver override that's actually honored (by the `cc` crate and
+        // friends driving any `*-sys` build scripts) is the `AR`/`AR_<target>`
+        // environment variable convention, same as `CC_<target>`.
+        cargo_build.env("AR", &archiver);
+        if let Some(triple) = OPTS.triple() {
+            cargo_build.env(format!("AR_{}", triple.replace('-', "_")), &archiver);
         }
     }
+    for flag in OPTS.codegen_flags() {
+        emit_flags.push_str(&format!(" -C {}", flag));
+    }
+    for arg in OPTS.llvm_args() {
+        emit_flags.push_str(&format!(" -C llvm-args={}", arg));
+    }
+
+    let effective_rustflags = if per_crate {
+        cargo_build.arg("--");
+        for flag in emit_flags.split_whitespace() {
+            cargo_build.arg(flag);
+        }
+        format!("{} {}", rustflags, emit_flags)
+    } else {
+        let flags = format!("{} {}", rustflags, emit_flags);
+        cargo_build.env("RUSTFLAGS", &flags);
+        flags
+    };
+
+    let cache_key = crate::cache::CacheKey::current(&effective_rustflags, emit_tag);
+    if let Some(cached) = crate::cache::lookup(&cache_key) {
+        debug!("cache hit for current build, skipping cargo build");
+        return cached;
+    }
 
     debug!("starting cargo build... {:?}", cargo_build);
     let error_msg = "cargo build failed";
-    process::exec(&mut cargo_build, error_msg, OPTS.debug_mode()).expect(error_msg);
+
+    cargo_build.stdout(Stdio::piped());
+    cargo_build.stderr(Stdio::piped());
+    let mut child = cargo_build.spawn().expect(error_msg);
+
+    let stdout = child.stdout.take().expect("failed to capture cargo's stdout");
+    let stderr = child.stderr.take().expect("failed to capture cargo's stderr");
+
+    // Cargo's stdout/stderr pipes are both bounded (64KiB on Linux), and with
+    // a big enough dependency graph either one can fill up while we're still
+    // draining the other; cargo then blocks on the write and we'd deadlock
+    // waiting for a stream that'll never reach EOF. Drain both concurrently.
+    let stdout_reader = ::std::thread::spawn(move || {
+        // The `compiler-artifact` messages on stdout tell us which crates
+        // cargo actually built as part of this invocation; `compiler-message`
+        // is rustc's only channel for diagnostics in this mode (it's invoked
+        // with `--error-format=json`, so nothing reaches its own stderr), so
+        // we hang on to the rendered text in case the build fails.
+        let mut built_crates = ::std::collections::HashSet::new();
+        let mut diagnostics = String::new();
+        for line in BufReader::new(stdout).lines() {
+            let line = line.expect("failed to read cargo's stdout");
+            debug!("cargo: {}", line);
+            let message: serde_json::Value = match serde_json::from_str(&line) {
+                Ok(message) => message,
+                // Not every line cargo prints with `--message-format=json`
+                // is itself JSON (e.g. it forwards raw rustc diagnostics).
+                Err(_) => continue,
+            };
+            match message["reason"].as_str() {
+                Some("compiler-artifact") => {
+                    if let Some(name) = message["target"]["name"].as_str() {
+                        built_crates.insert(name.replace('-', "_"));
+                    }
+                }
+                Some("compiler-message") => {
+                    if let Some(rendered) = message["message"]["rendered"].as_str() {
+                        diagnostics.push_str(rendered);
+                    }
+                }
+                _ => {}
+            }
+        }
+        (built_crates, diagnostics)
+    });
+
+    let stderr_reader = ::std::thread::spawn(move || {
+        // ...while the verbose `Running \`rustc ...\`` lines on stderr tell
+        // us where each crate's output lands, since cargo has no notion of
+        // the `.s`/`.ll` file produced as a side effect of `--emit`.
+        let mut invocations = Vec::new();
+        for line in BufReader::new(stderr).lines() {
+            let line = line.expect("failed to read cargo's stderr");
+            debug!("cargo: {}", line);
+            // `--no-color` only asks cargo/rustc not to emit ANSI escapes
+            // (handled by the `--color=always` arg we pass); it doesn't mean
+            // "suppress output", so these lines are always echoed.
+            eprintln!("{}", line);
+            if let Some(invocation) = parse_rustc_invocation(&line) {
+                invocations.push(invocation);
+            }
+        }
+        invocations
+    });
+
+    let (built_crates, diagnostics) = stdout_reader.join().expect("stdout reader thread panicked");
+    let invocations = stderr_reader.join().expect("stderr reader thread panicked");
+
+    let status = child.wait().expect(error_msg);
+    if !status.success() {
+        if diagnostics.is_empty() {
+            crate::display::write_error(error_msg);
+        } else {
+            crate::display::write_error(&diagnostics);
+        }
+        ::std::process::exit(1);
+    }
     debug!("cargo build finished...");
 
     let ext = match *OPTS.read() {
         crate::options::Options::Asm(_) => "s",
         crate::options::Options::LlvmIr(_) => "ll",
+        crate::options::Options::Mir(_) => "mir",
     };
 
-    let deps_directory = crate::target::directory("deps");
-
-    let mut output_files = vec![];
+    let emit_marker = emit_flags
+        .split_whitespace()
+        .find(|flag| flag.starts_with("--emit"))
+        .unwrap_or("--emit");
 
-    // Scan files in "deps" target dir:
-    output_files.append(&mut scan_directory(
-        deps_directory.as_path(),
-        |_, extension| extension == Some(ext),
-    ));
-
-    if let Some(example) = OPTS.example() {
-        let example_directory = crate::target::directory("examples");
-        let prefix = format!("{}-", example);
-
-        // Scan files in "examples" target dir, while making sure
-        // to only scanning those files belonging to the compiled example:
-        output_files.append(&mut scan_directory(
-            example_directory.as_path(),
-            |stem, extension| {
-                let has_prefix = stem.map_or(false, |stem| stem.starts_with(&prefix));
-                let has_extension = extension == Some(ext);
-                has_prefix && has_extension
-            },
-        ));
-    }
+    // Only keep the invocations for crates cargo actually (re)built that
+    // were themselves passed the `--emit` flag we're after (in per-crate
+    // mode, dependencies are compiled without it), and for the requested
+    // example or bin, if any — eliminating false matches from leftover or
+    // unrelated crates sharing the same `deps` directory.
+    let mut output_files: Vec<(PathBuf, String)> = invocations
+        .into_iter()
+        .filter(|inv| built_crates.contains(&inv.crate_name))
+        .filter(|inv| inv.command.contains(emit_marker))
+        .filter(|inv| {
+            OPTS.example()
+                .map_or(true, |example| inv.crate_name == example.replace('-', "_"))
+        })
+        .filter(|inv| {
+            OPTS.bin()
+                .map_or(true, |bin| inv.crate_name == bin.replace('-', "_"))
+        })
+        .map(|inv| {
+            let file_name = format!("{}{}.{}", inv.crate_name, inv.extra_filename, ext);
+            (inv.out_dir.join(file_name), inv.crate_name)
+        })
+        .collect();
 
     // Canonicalize, sort the files, remove duplicates, and done:
     if !cfg!(target_os = "windows") {
         // FIXME: On windows canonicalizing makes the path use UNC, but the
         // paths in the assembly emitted by rustc do not use UNC and they are
         // not currently canonicalized.
-        for f in &mut output_files {
+        for (f, _) in &mut output_files {
             let c = f.canonicalize().unwrap();
             debug!("canonicalize path {} into {}", f.display(), c.display());
             *f = c;
@@ -167,32 +369,37 @@ pub fn project() -> Vec<PathBuf> {
     }
     output_files.sort_unstable();
     output_files.dedup();
+
+    if let Err(e) = crate::cache::store(&cache_key, &output_files) {
+        debug!("failed to write asm cache: {}", e);
+    }
+
     output_files
 }
 
-/// Scan a given output directory for files matching the predicate:
-fn scan_directory<P>(target_directory: &Path, predicate: P) -> Vec<PathBuf>
-where
-    P: Fn(Option<&str>, Option<&str>) -> bool,
-{
-    let mut output_files = Vec::new();
-    for entry in ::walkdir::WalkDir::new(&(*target_directory)) {
-        let e = entry.unwrap_or_else(|_| {
-            panic!(
-                "failed to iterate over the directory: {}",
-                target_directory.display()
-            )
-        });
-        let p = e.path();
-
-        let stem = p.file_stem().and_then(|v| v.to_str());
-        let extension = p.extension().and_then(|v| v.to_str());
-
-        if predicate(stem, extension) {
-            let p = p.to_path_buf();
-            debug!("found file matching predicate: {}", p.display());
-            output_files.push(p);
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rustc_invocation_matches_resolved_toolchain_path() {
+        let line = r#"     Running `/root/.rustup/toolchains/stable-x86_64-unknown-linux-gnu/bin/rustc --crate-name foo --edition=2018 src/lib.rs --out-dir /tmp/target/debug/deps -C extra-filename=-abc123 --emit=asm`"#;
+        let invocation = parse_rustc_invocation(line).expect("line should be recognized as a rustc invocation");
+        assert_eq!(invocation.crate_name, "foo");
+        assert_eq!(invocation.out_dir, PathBuf::from("/tmp/target/debug/deps"));
+        assert_eq!(invocation.extra_filename, "-abc123");
+    }
+
+    #[test]
+    fn parse_rustc_invocation_matches_windows_exe_suffix() {
+        let line = r#"     Running `C:\Users\me\.rustup\toolchains\stable\bin\rustc.exe --crate-name foo --out-dir C:\proj\target\debug\deps -C extra-filename=-abc123 --emit=asm`"#;
+        let invocation = parse_rustc_invocation(line).expect("line should be recognized as a rustc invocation");
+        assert_eq!(invocation.crate_name, "foo");
+    }
+
+    #[test]
+    fn parse_rustc_invocation_ignores_non_rustc_lines() {
+        assert!(parse_rustc_invocation("   Compiling foo v0.1.0").is_none());
+        assert!(parse_rustc_invocation(r#"     Running `/usr/bin/cc -o foo foo.o`"#).is_none());
     }
-    output_files
 }
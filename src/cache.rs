@@ -0,0 +1,250 @@
+//! Content-addressed cache for the files [`crate::build::project`] emits.
+
+use super::*;
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    fs, io,
+    hash::{Hash, Hasher},
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+/// Everything that determines whether a previous build can be reused
+/// instead of recompiling.
+pub struct CacheKey {
+    source_hashes: Vec<(PathBuf, u64)>,
+    rustflags: String,
+    build_type_tag: &'static str,
+    triple: Option<String>,
+    emit_tag: &'static str,
+    rustc_version: String,
+    package: Option<String>,
+    example: Option<String>,
+    bin: Option<String>,
+    features: Vec<String>,
+    no_default_features: bool,
+    lib: bool,
+    tests: bool,
+    benches: bool,
+    whole_workspace: bool,
+}
+
+impl CacheKey {
+    /// Build the cache key for the current CLI invocation.
+    pub fn current(rustflags: &str, emit_tag: &'static str) -> CacheKey {
+        CacheKey {
+            source_hashes: hash_sources(),
+            rustflags: rustflags.to_string(),
+            build_type_tag: match OPTS.build_type() {
+                crate::build::Type::Debug => "debug",
+                crate::build::Type::Release => "release",
+            },
+            triple: OPTS.triple().map(str::to_string),
+            emit_tag,
+            rustc_version: rustc_version(),
+            package: OPTS.package().map(str::to_string),
+            example: OPTS.example().map(str::to_string),
+            bin: OPTS.bin().map(str::to_string),
+            features: OPTS.features(),
+            no_default_features: OPTS.no_default_features(),
+            lib: OPTS.lib(),
+            tests: OPTS.tests(),
+            benches: OPTS.benches(),
+            whole_workspace: OPTS.whole_workspace(),
+        }
+    }
+
+    /// Hash the key down to a single directory-safe digest.
+    fn digest(&self) -> String {
+        let mut hasher = DefaultHasher::new();
+        self.source_hashes.hash(&mut hasher);
+        self.rustflags.hash(&mut hasher);
+        self.build_type_tag.hash(&mut hasher);
+        self.triple.hash(&mut hasher);
+        self.emit_tag.hash(&mut hasher);
+        self.rustc_version.hash(&mut hasher);
+        self.package.hash(&mut hasher);
+        self.example.hash(&mut hasher);
+        self.bin.hash(&mut hasher);
+        self.features.hash(&mut hasher);
+        self.no_default_features.hash(&mut hasher);
+        self.lib.hash(&mut hasher);
+        self.tests.hash(&mut hasher);
+        self.benches.hash(&mut hasher);
+        self.whole_workspace.hash(&mut hasher);
+        format!("{:016x}", hasher.finish())
+    }
+}
+
+/// Directories that never hold anything worth tracking.
+const SKIP_DIRS: &[&str] = &["target", ".git"];
+
+/// Hash every tracked `.rs` source file reachable from the current
+/// directory, keyed by path, using the mtime as a fast stand-in for hashing
+/// file contents.
+fn hash_sources() -> Vec<(PathBuf, u64)> {
+    hash_sources_under(Path::new("."))
+}
+
+fn hash_sources_under(root: &Path) -> Vec<(PathBuf, u64)> {
+    let mut hashes = Vec::new();
+    let walker = ::walkdir::WalkDir::new(root)
+        .into_iter()
+        .filter_entry(|entry| {
+            entry
+                .file_name()
+                .to_str()
+                .map_or(true, |name| !SKIP_DIRS.contains(&name))
+        });
+    for entry in walker {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(_) => continue,
+        };
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("rs") {
+            continue;
+        }
+        if let Some(modified) = entry.metadata().ok().and_then(|m| m.modified().ok()) {
+            let mut hasher = DefaultHasher::new();
+            modified.hash(&mut hasher);
+            hashes.push((path.to_path_buf(), hasher.finish()));
+        }
+    }
+    hashes.sort();
+    hashes
+}
+
+/// Run `rustc -Vv`, honoring the `RUSTC` override the same way `build::project`
+/// does.
+fn rustc_version() -> String {
+    let rustc = ::std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    Command::new(rustc)
+        .arg("-Vv")
+        .output()
+        .ok()
+        .map(|o| String::from_utf8_lossy(&o.stdout).into_owned())
+        .unwrap_or_default()
+}
+
+fn cache_directory(digest: &str) -> PathBuf {
+    crate::target::directory("asm-cache").join(digest)
+}
+
+/// The manifest lists each cached file next to the crate name that produced
+/// it, one per line, as `<crate_name>\t<path>`.
+fn manifest_path(dir: &Path) -> PathBuf {
+    dir.join("manifest.txt")
+}
+
+/// Look up a previous build matching `key`, returning its file list on a hit.
+pub fn lookup(key: &CacheKey) -> Option<Vec<(PathBuf, String)>> {
+    if OPTS.no_cache() {
+        return None;
+    }
+    let hit = lookup_in(&cache_directory(&key.digest()))?;
+    debug!("asm cache hit for key {}", key.digest());
+    Some(hit)
+}
+
+/// Record `files` as the result of building with `key`, for reuse next time.
+pub fn store(key: &CacheKey, files: &[(PathBuf, String)]) -> io::Result<()> {
+    store_in(&cache_directory(&key.digest()), files)
+}
+
+fn lookup_in(dir: &Path) -> Option<Vec<(PathBuf, String)>> {
+    let manifest = fs::read_to_string(manifest_path(dir)).ok()?;
+
+    let mut files = Vec::new();
+    for line in manifest.lines() {
+        let (crate_name, path) = line.split_once('\t')?;
+        let path = PathBuf::from(path);
+        if !path.exists() {
+            // A cached file went missing (e.g. the target dir was cleaned);
+            // treat this as a miss rather than handing back a dangling path.
+            return None;
+        }
+        files.push((path, crate_name.to_string()));
+    }
+    Some(files)
+}
+
+fn store_in(dir: &Path, files: &[(PathBuf, String)]) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+
+    let manifest: String = files
+        .iter()
+        .map(|(path, crate_name)| format!("{}\t{}\n", crate_name, path.display()))
+        .collect();
+
+    fs::write(manifest_path(dir), manifest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = ::std::env::temp_dir().join(format!("cargo-asm-cache-test-{}-{}", name, ::std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn hash_sources_skips_target_and_git() {
+        let dir = temp_dir("hash-sources");
+        fs::write(dir.join("lib.rs"), "fn main() {}").unwrap();
+        fs::create_dir_all(dir.join("target/debug")).unwrap();
+        fs::write(dir.join("target/debug/generated.rs"), "fn bad() {}").unwrap();
+        fs::create_dir_all(dir.join(".git")).unwrap();
+        fs::write(dir.join(".git/whatever.rs"), "fn also_bad() {}").unwrap();
+
+        let hashes = hash_sources_under(&dir);
+        assert_eq!(hashes.len(), 1);
+        assert_eq!(hashes[0].0, dir.join("lib.rs"));
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn store_then_lookup_round_trips() {
+        let cache_dir = temp_dir("store-lookup");
+        let artifact_dir = temp_dir("store-lookup-artifact");
+        let artifact = artifact_dir.join("foo.s");
+        fs::write(&artifact, "movq %rax, %rdi").unwrap();
+
+        let files = vec![(artifact.clone(), "foo".to_string())];
+        store_in(&cache_dir, &files).unwrap();
+
+        let hit = lookup_in(&cache_dir).expect("store'd cache should be a hit");
+        assert_eq!(hit, files);
+
+        let _ = fs::remove_dir_all(&cache_dir);
+        let _ = fs::remove_dir_all(&artifact_dir);
+    }
+
+    #[test]
+    fn lookup_misses_when_a_cached_file_went_missing() {
+        let cache_dir = temp_dir("stale-lookup");
+        let artifact_dir = temp_dir("stale-lookup-artifact");
+        let artifact = artifact_dir.join("foo.s");
+        fs::write(&artifact, "movq %rax, %rdi").unwrap();
+
+        store_in(&cache_dir, &[(artifact.clone(), "foo".to_string())]).unwrap();
+        fs::remove_file(&artifact).unwrap();
+
+        assert!(lookup_in(&cache_dir).is_none());
+
+        let _ = fs::remove_dir_all(&cache_dir);
+        let _ = fs::remove_dir_all(&artifact_dir);
+    }
+
+    #[test]
+    fn lookup_misses_when_theres_no_manifest() {
+        let cache_dir = temp_dir("empty-lookup");
+        assert!(lookup_in(&cache_dir).is_none());
+        let _ = fs::remove_dir_all(&cache_dir);
+    }
+}